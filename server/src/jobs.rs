@@ -0,0 +1,415 @@
+// SPDX-FileCopyrightText: © 2022 ChiselStrike <info@chiselstrike.com>
+
+//! Durable background job queue, backed by the managed `chisel_job_queue`
+//! table rather than an in-memory channel, so enqueued work survives a
+//! server restart and can be enqueued transactionally with the request
+//! that scheduled it.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{ensure, Context, Result};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value as JsonValue;
+use uuid::Uuid;
+
+use crate::datastore::engine::{SqlParam, Transaction};
+use crate::server::Server;
+use crate::QueryEngine;
+
+pub type JobId = Uuid;
+
+/// Base delay for the first retry; doubled for every subsequent attempt and
+/// capped at `MAX_BACKOFF`.
+const BASE_RETRY_DELAY: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(15 * 60);
+/// A `running` job whose heartbeat is older than this is assumed to belong
+/// to a crashed worker and is returned to `new` by the reaper.
+pub const DEFAULT_LEASE_TIMEOUT: Duration = Duration::from_secs(30);
+/// Default gap between heartbeats sent while a handler is running, kept
+/// well under `DEFAULT_LEASE_TIMEOUT` so a missed beat or two doesn't cause
+/// the reaper to mistake live work for a crashed worker.
+const DEFAULT_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+/// A single handler invocation taking longer than this logs a warning by
+/// default, so stuck handlers are visible instead of silently blocking the
+/// queue. `JobWorker::new` takes this as a parameter so a deployment can
+/// tune it without a code change.
+pub const DEFAULT_SLOW_HANDLER_WARNING: Duration = Duration::from_secs(5);
+/// Number of attempts (including the first) before a failing job is
+/// dead-lettered.
+const DEFAULT_MAX_RETRIES: u32 = 5;
+
+/// Status column of a row in `chisel_job_queue`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    New,
+    Running,
+    Complete,
+    Failed,
+}
+
+/// A single row of the managed `chisel_job_queue` table.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Job {
+    pub id: JobId,
+    /// Version that enqueued this job, so jobs never leak across versions:
+    /// a job_id handed back by one version's JS (dequeue, heartbeat,
+    /// complete, fail) must never let it act on another version's job.
+    pub version_id: String,
+    pub queue: String,
+    pub payload: JsonValue,
+    pub status: JobStatus,
+    pub retry_count: u32,
+    pub max_retries: u32,
+}
+
+/// Error a handler invocation fails with when a job's payload cannot be
+/// deserialized into the shape its handler expects. Unlike any other `Err`,
+/// this is not retried: a malformed payload will never become valid, so the
+/// job is dead-lettered immediately.
+#[derive(Debug)]
+pub struct InvalidJob(pub String);
+
+impl fmt::Display for InvalidJob {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid job payload: {}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidJob {}
+
+fn backoff_delay(retry_count: u32) -> Duration {
+    let factor = 1u64.checked_shl(retry_count).unwrap_or(u64::MAX);
+    // Multiply in u64 and cap before converting to a `Duration`, so a large
+    // `retry_count` saturates at `MAX_BACKOFF` instead of wrapping through a
+    // premature `u32` cast.
+    let secs = BASE_RETRY_DELAY
+        .as_secs()
+        .saturating_mul(factor)
+        .min(MAX_BACKOFF.as_secs());
+    Duration::from_secs(secs)
+}
+
+/// Whether a job failing now, with `retry_count` retries already used, has
+/// run out of its `max_retries` attempts and must be dead-lettered.
+fn retries_exhausted(retry_count: u32, max_retries: u32) -> bool {
+    retry_count + 1 >= max_retries
+}
+
+/// Claims and updates rows of `chisel_job_queue` on behalf of the worker
+/// loop in [`JobWorker`]. One `JobQueue` is shared by the whole
+/// server, the same way `QueryEngine` is.
+pub struct JobQueue {
+    query_engine: QueryEngine,
+}
+
+impl JobQueue {
+    pub fn new(query_engine: QueryEngine) -> Self {
+        Self { query_engine }
+    }
+
+    /// Inserts a `new` row for `queue_name` with `payload` as its JSON body,
+    /// using the caller's transaction so the insert rolls back together with
+    /// the rest of the request. `payload` is arbitrary, caller-controlled
+    /// JSON, so it (and `queue_name`) are bound as query parameters rather
+    /// than interpolated into the SQL text.
+    pub async fn enqueue(
+        &self,
+        version_id: &str,
+        queue_name: &str,
+        payload: JsonValue,
+        transaction: &mut Transaction,
+    ) -> Result<JobId> {
+        let id = Uuid::new_v4();
+        self.query_engine
+            .execute_sql_params(
+                transaction,
+                "INSERT INTO chisel_job_queue \
+                 (id, version_id, queue, job, status, retry_count, max_retries) \
+                 VALUES ($1, $2, $3, $4, 'new', 0, $5)",
+                &[
+                    SqlParam::Uuid(id),
+                    SqlParam::Text(version_id.to_string()),
+                    SqlParam::Text(queue_name.to_string()),
+                    SqlParam::Json(payload),
+                    SqlParam::Int(DEFAULT_MAX_RETRIES as i64),
+                ],
+            )
+            .await?;
+        Ok(id)
+    }
+
+    /// Claims the oldest `new` job for `(version_id, queue_name)` whose
+    /// `run_after` has elapsed, using `SELECT ... FOR UPDATE SKIP LOCKED`
+    /// (or the SQLite equivalent) so concurrent workers never race on the
+    /// same row, marks it `running`, and stamps its initial heartbeat.
+    /// Scoped by `version_id` as well as `queue_name` so two versions that
+    /// happen to register a queue with the same name never see each other's
+    /// jobs.
+    pub async fn claim_next(&self, version_id: &str, queue_name: &str) -> Result<Option<Job>> {
+        self.query_engine
+            .claim_next_job(version_id, queue_name)
+            .await
+    }
+
+    /// Looks up `job_id` and checks it belongs to `version_id`, so a
+    /// version can't act on a job_id that belongs to another version (e.g.
+    /// one guessed or leaked out of band).
+    async fn load_owned_job(&self, version_id: &str, job_id: JobId) -> Result<Job> {
+        let job = self
+            .query_engine
+            .load_job(job_id)
+            .await?
+            .context("no such job")?;
+        ensure!(
+            job.version_id == version_id,
+            "job {job_id} does not belong to this version"
+        );
+        Ok(job)
+    }
+
+    /// Updates `heartbeat` on a `running` job so the reaper knows its worker
+    /// is still alive. Trusted/internal: the caller must already know
+    /// `job_id` belongs to the version it's acting as, e.g. because it came
+    /// from `claim_next`. Use `touch_heartbeat_by_id` when `job_id` instead
+    /// comes from an untrusted caller. Should be called periodically while
+    /// the handler runs.
+    pub async fn touch_heartbeat(&self, job_id: JobId) -> Result<()> {
+        self.query_engine.touch_job_heartbeat(job_id).await
+    }
+
+    /// Same as `touch_heartbeat`, but first verifies `job_id` belongs to
+    /// `version_id`. Used by `op_chisel_job_heartbeat`, since a queue-handler
+    /// script could otherwise pass a job_id that belongs to another version.
+    pub async fn touch_heartbeat_by_id(&self, version_id: &str, job_id: JobId) -> Result<()> {
+        self.load_owned_job(version_id, job_id).await?;
+        self.touch_heartbeat(job_id).await
+    }
+
+    /// Marks `job_id` as `complete`. Trusted/internal; see `touch_heartbeat`.
+    pub async fn complete(&self, job_id: JobId) -> Result<()> {
+        self.query_engine
+            .set_job_status(job_id, JobStatus::Complete, None)
+            .await
+    }
+
+    /// Same as `complete`, but first verifies `job_id` belongs to
+    /// `version_id`. Used by `op_chisel_complete_job`.
+    pub async fn complete_by_id(&self, version_id: &str, job_id: JobId) -> Result<()> {
+        self.load_owned_job(version_id, job_id).await?;
+        self.complete(job_id).await
+    }
+
+    /// A handler threw for `job`. If retries remain, reschedules it back to
+    /// `new` with `run_after` computed from an exponential backoff; once
+    /// `max_retries` is exhausted, moves it to the dead-letter `failed`
+    /// state, retaining `error`.
+    ///
+    /// `job` must be a row this `JobQueue` just loaded itself (e.g. what
+    /// `claim_next` returned or `fail_by_id` looked up) rather than data handed back
+    /// by an untrusted caller: the retry/backoff decision below trusts
+    /// `job.retry_count`/`job.max_retries` as-is.
+    pub async fn fail(&self, job: &Job, error: String) -> Result<()> {
+        if retries_exhausted(job.retry_count, job.max_retries) {
+            self.query_engine
+                .set_job_status(job.id, JobStatus::Failed, Some(error))
+                .await
+        } else {
+            let delay = backoff_delay(job.retry_count);
+            self.query_engine
+                .reschedule_job(job.id, job.retry_count + 1, delay, error)
+                .await
+        }
+    }
+
+    /// Looks up `job_id`'s current row, checks it belongs to `version_id`,
+    /// and applies the retry/backoff decision against *that*, rather than
+    /// trusting caller-supplied `retry_count`/`max_retries`. Used by
+    /// `op_chisel_fail_job`, since a queue-handler script could otherwise
+    /// hand back a doctored `Job` to dodge dead-lettering or a job_id
+    /// belonging to another version, or the row could have changed between
+    /// dequeue and fail (e.g. the reaper already reset it).
+    pub async fn fail_by_id(&self, version_id: &str, job_id: JobId, error: String) -> Result<()> {
+        let job = self.load_owned_job(version_id, job_id).await?;
+        self.fail(&job, error).await
+    }
+
+    /// Dead-letters `job_id` immediately without consuming a retry, used
+    /// when the payload itself is malformed (see [`InvalidJob`]) rather than
+    /// when the handler failed transiently.
+    pub async fn fail_permanently(&self, job_id: JobId, error: InvalidJob) -> Result<()> {
+        self.query_engine
+            .set_job_status(job_id, JobStatus::Failed, Some(error.to_string()))
+            .await
+    }
+
+    /// Returns `running` jobs whose heartbeat is older than `lease_timeout`
+    /// back to `new`, recovering work left behind by a worker that crashed
+    /// mid-job. Intended to run periodically from the same loop that polls
+    /// for new jobs.
+    pub async fn reap_stale(&self, lease_timeout: Duration) -> Result<u64> {
+        self.query_engine.reap_stale_jobs(lease_timeout).await
+    }
+}
+
+/// Tracks which queues have a JS handler registered, via
+/// `op_chisel_register_job_queue`, so the worker loop knows what to poll.
+/// Keyed by `(version_id, queue_name)`, not just `queue_name`, so two
+/// versions that happen to pick the same queue name are polled and
+/// dispatched independently. Shared by the whole server, the same way
+/// `JobQueue` is.
+#[derive(Default)]
+pub struct JobQueueRegistry {
+    queues: Mutex<HashSet<(String, String)>>,
+}
+
+impl JobQueueRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register(&self, version_id: String, queue_name: String) {
+        self.queues.lock().unwrap().insert((version_id, queue_name));
+    }
+
+    fn queue_names(&self) -> Vec<(String, String)> {
+        self.queues.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+/// Drives automatic execution of background jobs for one server: polls
+/// every registered `(version_id, queue_name)`, claims one job at a time,
+/// invokes the JS handler declared for that queue (the same module-dispatch
+/// `Server` uses to run an HTTP route), and marks the job `complete`/`failed`
+/// on return. Spawned once at server startup and runs for the server's
+/// lifetime.
+pub struct JobWorker {
+    server: Arc<Server>,
+    poll_interval: Duration,
+    /// Logged as a warning when a single handler invocation runs longer
+    /// than this. Deliberately a field rather than a hardcoded const, so a
+    /// deployment with naturally slow handlers can raise it without log
+    /// spam becoming the normal case.
+    slow_handler_warning: Duration,
+}
+
+impl JobWorker {
+    pub fn new(
+        server: Arc<Server>,
+        poll_interval: Duration,
+        slow_handler_warning: Duration,
+    ) -> Self {
+        Self {
+            server,
+            poll_interval,
+            slow_handler_warning,
+        }
+    }
+
+    pub async fn run(self) {
+        loop {
+            for (version_id, queue_name) in self.server.job_queue_registry.queue_names() {
+                if let Err(err) = self.poll_once(&version_id, &queue_name).await {
+                    log::warn!(
+                        "job worker: error polling queue '{queue_name}' for version '{version_id}': {err:#}"
+                    );
+                }
+            }
+            if let Err(err) = self
+                .server
+                .job_queue
+                .reap_stale(DEFAULT_LEASE_TIMEOUT)
+                .await
+            {
+                log::warn!("job worker: error reaping stale jobs: {err:#}");
+            }
+            tokio::time::sleep(self.poll_interval).await;
+        }
+    }
+
+    async fn poll_once(&self, version_id: &str, queue_name: &str) -> Result<()> {
+        let Some(job) = self
+            .server
+            .job_queue
+            .claim_next(version_id, queue_name)
+            .await?
+        else {
+            return Ok(());
+        };
+
+        // Keep the heartbeat fresh for as long as the handler runs, so a
+        // long-running handler isn't mistaken by the reaper for a crashed
+        // worker and reaped mid-execution. Aborted as soon as the handler
+        // returns below.
+        let job_id = job.id;
+        let server = self.server.clone();
+        let heartbeat_task = tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(DEFAULT_HEARTBEAT_INTERVAL).await;
+                if let Err(err) = server.job_queue.touch_heartbeat(job_id).await {
+                    log::warn!("job worker: failed to send heartbeat for job {job_id}: {err:#}");
+                }
+            }
+        });
+
+        // Time the handler invocation itself, not the claim: a slow claim
+        // means the datastore is under load, but a slow handler means the
+        // JS callback is stuck, which is the case worth a warning here.
+        let started = std::time::Instant::now();
+        let outcome = self.server.invoke_job_handler(&job).await;
+        heartbeat_task.abort();
+
+        let elapsed = started.elapsed();
+        if elapsed > self.slow_handler_warning {
+            log::warn!(
+                "handler for job {job_id} on queue '{queue_name}' took {elapsed:?}, \
+                 exceeding the {:?} threshold",
+                self.slow_handler_warning
+            );
+        }
+
+        match outcome {
+            Ok(()) => self.server.job_queue.complete(job_id).await,
+            // A malformed payload will never become valid: dead-letter it
+            // now instead of spending retries on it.
+            Err(error) => match error.downcast::<InvalidJob>() {
+                Ok(invalid) => self.server.job_queue.fail_permanently(job_id, invalid).await,
+                Err(error) => self.server.job_queue.fail(&job, error.to_string()).await,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_delay_doubles_up_to_the_cap() {
+        assert_eq!(backoff_delay(0), Duration::from_secs(1));
+        assert_eq!(backoff_delay(1), Duration::from_secs(2));
+        assert_eq!(backoff_delay(4), Duration::from_secs(16));
+        assert_eq!(backoff_delay(10), MAX_BACKOFF);
+    }
+
+    #[test]
+    fn backoff_delay_saturates_instead_of_overflowing() {
+        // `retry_count` this large would overflow a `u32` shift/cast long
+        // before reaching `MAX_BACKOFF`; it must saturate, not wrap.
+        assert_eq!(backoff_delay(63), MAX_BACKOFF);
+        assert_eq!(backoff_delay(u32::MAX), MAX_BACKOFF);
+    }
+
+    #[test]
+    fn fail_dead_letters_only_once_retries_are_exhausted() {
+        // `fail` itself talks to `query_engine`, which this trimmed tree
+        // doesn't have a fake for; exercise the pure threshold decision it
+        // is built on (`retries_exhausted`) instead.
+        assert!(retries_exhausted(DEFAULT_MAX_RETRIES - 1, DEFAULT_MAX_RETRIES));
+
+        assert!(!retries_exhausted(0, DEFAULT_MAX_RETRIES));
+    }
+}
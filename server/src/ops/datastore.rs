@@ -8,9 +8,12 @@ use std::sync::Arc;
 use std::task::{Context, Poll};
 
 use anyhow::{anyhow, bail, ensure, Context as _, Result};
+use dashmap::mapref::entry::Entry;
+use dashmap::DashMap;
 use deno_core::error::AnyError;
 use deno_core::{serde_v8, v8, CancelFuture};
 use serde_derive::Deserialize;
+use tokio::sync::oneshot;
 
 use super::WorkerState;
 use crate::datastore::engine::{IdTree, QueryResults, TransactionStatic};
@@ -36,16 +39,58 @@ pub struct ChiselRequestContext {
     user_id: Option<String>,
 }
 
+impl ChiselRequestContext {
+    /// Exposed so sibling op modules (e.g. `ops::jobs`) can scope their own
+    /// state by version without reaching into private fields.
+    pub(crate) fn version_id(&self) -> &str {
+        &self.version_id
+    }
+}
+
+/// Isolation level requested for the outermost transaction in a request.
+/// Nested savepoints always inherit the isolation of the transaction they
+/// are opened within, since SQL does not allow changing isolation mid-flight.
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+pub enum IsolationLevel {
+    ReadCommitted,
+    RepeatableRead,
+    Serializable,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct BeginTransactionParams {
+    isolation: Option<IsolationLevel>,
+    #[serde(default)]
+    read_only: bool,
+}
+
+/// Begins a transaction, or, if one is already in progress, opens a nested
+/// savepoint on top of it. This lets data helpers call each other freely
+/// without needing to know whether a caller already started a transaction.
+///
+/// `params` is only honored for the outermost transaction: a read-only,
+/// serializable-isolation transaction gives callers retryable analytics
+/// reads without the risk of an accidental write.
 #[deno_core::op]
-pub async fn op_chisel_begin_transaction(state: Rc<RefCell<deno_core::OpState>>) -> Result<()> {
-    let query_engine = state
-        .borrow()
-        .borrow::<WorkerState>()
-        .server
-        .query_engine
-        .clone();
-    let transaction = query_engine.begin_transaction_static().await?;
-    {
+pub async fn op_chisel_begin_transaction(
+    state: Rc<RefCell<deno_core::OpState>>,
+    params: BeginTransactionParams,
+) -> Result<()> {
+    let (query_engine, depth) = {
+        let state = state.borrow();
+        let worker_state = state.borrow::<WorkerState>();
+        (
+            worker_state.server.query_engine.clone(),
+            worker_state.transaction_depth,
+        )
+    };
+
+    if depth == 0 {
+        let transaction = query_engine
+            .begin_transaction_static(params.isolation, params.read_only)
+            .await?;
         let mut state = state.borrow_mut();
         let worker_state = state.borrow_mut::<WorkerState>();
         ensure!(
@@ -53,12 +98,85 @@ pub async fn op_chisel_begin_transaction(state: Rc<RefCell<deno_core::OpState>>)
             "Cannot begin a transaction because another transaction is in progress"
         );
         worker_state.transaction = Some(transaction);
+        worker_state.transaction_depth = 1;
+        worker_state.transaction_read_only = params.read_only;
+    } else {
+        let transaction = state
+            .borrow()
+            .borrow::<WorkerState>()
+            .transaction
+            .clone()
+            .context("Cannot begin a transaction because no transaction is in progress")?;
+
+        // Hold the transaction's own lock across the whole read-SQL-write
+        // sequence below, not just around the `execute_sql` call: two
+        // concurrent nested `begin`s on the same transaction (e.g. from a
+        // `Promise.all`) could otherwise both read the same `transaction_depth`,
+        // open a `SAVEPOINT` with the same name, and then stomp on each
+        // other's write-back, corrupting the depth counter. The lock turns
+        // this whole sequence into one atomic step per caller.
+        let mut transaction = transaction.lock().await;
+        let depth = state.borrow().borrow::<WorkerState>().transaction_depth;
+        query_engine
+            .execute_sql(
+                &mut transaction,
+                &format!("SAVEPOINT chisel_sp_{}", depth + 1),
+            )
+            .await?;
+        state
+            .borrow_mut()
+            .borrow_mut::<WorkerState>()
+            .transaction_depth = depth + 1;
     }
     Ok(())
 }
 
 #[deno_core::op]
 pub async fn op_chisel_commit_transaction(state: Rc<RefCell<deno_core::OpState>>) -> Result<()> {
+    let (query_engine, depth) = {
+        let state = state.borrow();
+        let worker_state = state.borrow::<WorkerState>();
+        (
+            worker_state.server.query_engine.clone(),
+            worker_state.transaction_depth,
+        )
+    };
+    ensure!(
+        depth > 0,
+        "Cannot commit a transaction because no transaction is in progress"
+    );
+
+    if depth > 1 {
+        let transaction = state
+            .borrow()
+            .borrow::<WorkerState>()
+            .transaction
+            .clone()
+            .context("Cannot commit a transaction because no transaction is in progress")?;
+
+        // See the comment in `op_chisel_begin_transaction`: hold the
+        // transaction's lock across the re-read, the SQL, and the
+        // write-back so concurrent nested commits can't race on
+        // `transaction_depth`.
+        let mut transaction = transaction.lock().await;
+        let depth = state.borrow().borrow::<WorkerState>().transaction_depth;
+        ensure!(
+            depth > 1,
+            "Cannot commit a transaction because no transaction is in progress"
+        );
+        query_engine
+            .execute_sql(
+                &mut transaction,
+                &format!("RELEASE SAVEPOINT chisel_sp_{}", depth),
+            )
+            .await?;
+        state
+            .borrow_mut()
+            .borrow_mut::<WorkerState>()
+            .transaction_depth = depth - 1;
+        return Ok(());
+    }
+
     let transaction = state
         .borrow_mut()
         .borrow_mut::<WorkerState>()
@@ -73,12 +191,61 @@ pub async fn op_chisel_commit_transaction(state: Rc<RefCell<deno_core::OpState>>
         )?
         .into_inner();
     QueryEngine::commit_transaction(transaction).await?;
+    let mut state = state.borrow_mut();
+    let worker_state = state.borrow_mut::<WorkerState>();
+    worker_state.transaction_depth = 0;
+    worker_state.transaction_read_only = false;
     Ok(())
 }
 
 #[deno_core::op]
-pub fn op_chisel_rollback_transaction(state: &mut deno_core::OpState) -> Result<()> {
+pub async fn op_chisel_rollback_transaction(state: Rc<RefCell<deno_core::OpState>>) -> Result<()> {
+    let (query_engine, depth) = {
+        let state = state.borrow();
+        let worker_state = state.borrow::<WorkerState>();
+        (
+            worker_state.server.query_engine.clone(),
+            worker_state.transaction_depth,
+        )
+    };
+    ensure!(
+        depth > 0,
+        "Cannot rollback a transaction because no transaction is in progress"
+    );
+
+    if depth > 1 {
+        let transaction = state
+            .borrow()
+            .borrow::<WorkerState>()
+            .transaction
+            .clone()
+            .context("Cannot rollback a transaction because no transaction is in progress")?;
+
+        // See the comment in `op_chisel_begin_transaction`: hold the
+        // transaction's lock across the re-read, the SQL, and the
+        // write-back so concurrent nested rollbacks can't race on
+        // `transaction_depth`.
+        let mut transaction = transaction.lock().await;
+        let depth = state.borrow().borrow::<WorkerState>().transaction_depth;
+        ensure!(
+            depth > 1,
+            "Cannot rollback a transaction because no transaction is in progress"
+        );
+        query_engine
+            .execute_sql(
+                &mut transaction,
+                &format!("ROLLBACK TO SAVEPOINT chisel_sp_{}", depth),
+            )
+            .await?;
+        state
+            .borrow_mut()
+            .borrow_mut::<WorkerState>()
+            .transaction_depth = depth - 1;
+        return Ok(());
+    }
+
     let transaction = state
+        .borrow_mut()
         .borrow_mut::<WorkerState>()
         .transaction
         .take()
@@ -92,10 +259,25 @@ pub fn op_chisel_rollback_transaction(state: &mut deno_core::OpState) -> Result<
         .into_inner();
     // Drop the transaction, causing it to rollback.
     drop(transaction);
+    let mut state = state.borrow_mut();
+    let worker_state = state.borrow_mut::<WorkerState>();
+    worker_state.transaction_depth = 0;
+    worker_state.transaction_read_only = false;
     Ok(())
 }
 
-async fn with_transaction<F, Fut, T>(state: Rc<RefCell<deno_core::OpState>>, f: F) -> Result<T>
+fn ensure_not_read_only(state: &Rc<RefCell<deno_core::OpState>>) -> Result<()> {
+    ensure!(
+        !state.borrow().borrow::<WorkerState>().transaction_read_only,
+        "Cannot perform a data operation because the transaction is read-only"
+    );
+    Ok(())
+}
+
+pub(crate) async fn with_transaction<F, Fut, T>(
+    state: Rc<RefCell<deno_core::OpState>>,
+    f: F,
+) -> Result<T>
 where
     F: FnOnce(Arc<Server>, Arc<Version>, TransactionStatic) -> Fut,
     Fut: Future<Output = Result<T>>,
@@ -127,6 +309,7 @@ pub fn op_chisel_store<'a>(
     params: StoreParams<'a>,
     context: ChiselRequestContext,
 ) -> Result<impl Future<Output = Result<IdTree, AnyError>> + 'static, AnyError> {
+    ensure_not_read_only(&state)?;
     let v8_value = &params.value.v8_value;
     let value = EntityValue::from_v8(v8_value, scope)?;
 
@@ -155,6 +338,56 @@ pub fn op_chisel_store<'a>(
     })
 }
 
+#[derive(Deserialize)]
+pub struct StoreManyParams<'a> {
+    name: String,
+    values: Vec<serde_v8::Value<'a>>,
+}
+
+/// Batched version of `op_chisel_store`: converts every value once, looks up
+/// the entity type and checks auth a single time, then issues one multi-row
+/// `INSERT` instead of one row (and one awaited engine call) per value.
+/// Meant for import/seed endpoints writing many entities of the same type
+/// in a single request; the whole batch still runs under the active
+/// transaction, so it rolls back as a unit.
+#[deno_core::op(v8)]
+pub fn op_chisel_store_many<'a>(
+    scope: &mut v8::HandleScope<'a>,
+    state: Rc<RefCell<deno_core::OpState>>,
+    params: StoreManyParams<'a>,
+    context: ChiselRequestContext,
+) -> Result<impl Future<Output = Result<Vec<IdTree>, AnyError>> + 'static, AnyError> {
+    ensure_not_read_only(&state)?;
+    let mut values = Vec::with_capacity(params.values.len());
+    for value in &params.values {
+        values.push(EntityValue::from_v8(&value.v8_value, scope)?);
+    }
+
+    Ok(async move {
+        with_transaction(state, move |server, version, transaction| async move {
+            let ty = match version.type_system.lookup_type(&params.name) {
+                Ok(Type::Entity(ty)) => ty,
+                _ => bail!("Cannot save into type {}", params.name),
+            };
+            if ty.is_auth() && !is_auth_path(&context.version_id, &context.routing_path) {
+                bail!("Cannot save into auth type {}", params.name);
+            }
+
+            let rows = values
+                .iter()
+                .map(EntityValue::as_map)
+                .collect::<Result<Vec<_>>>()?;
+
+            let mut transaction = transaction.lock().await;
+            server
+                .query_engine
+                .add_rows(&ty, rows, &mut transaction, &version.type_system)
+                .await
+        })
+        .await
+    })
+}
+
 fn is_auth_path(version_id: &str, routing_path: &str) -> bool {
     version_id == "__chiselstrike" && routing_path.starts_with("/auth/")
 }
@@ -172,6 +405,7 @@ pub async fn op_chisel_delete(
     params: DeleteParams,
     context: ChiselRequestContext,
 ) -> Result<()> {
+    ensure_not_read_only(&state)?;
     with_transaction(state, move |server, version, transaction| async move {
         let mutation = Mutation::delete_from_expr(
             &RequestContext::new(&version.policy_system, &version.type_system, context),
@@ -203,6 +437,7 @@ pub async fn op_chisel_crud_delete(
     params: CrudDeleteParams,
     context: ChiselRequestContext,
 ) -> Result<()> {
+    ensure_not_read_only(&state)?;
     with_transaction(state, move |server, version, transaction| async move {
         let mutation = crud::delete_from_url_query(
             &RequestContext::new(&version.policy_system, &version.type_system, context),
@@ -223,22 +458,182 @@ pub async fn op_chisel_crud_delete(
     .await
 }
 
+/// Identifies a `op_chisel_crud_query` call for coalescing purposes. Used
+/// directly as the `DashMap` key rather than collapsed into a precomputed
+/// hash: `DefaultHasher` is `SipHash` with a fixed, not randomized, seed
+/// (unlike `HashMap`'s default `RandomState`), so a hash-only key would let
+/// two genuinely different requests collide onto the same bucket with no
+/// equality check to tell them apart. Keeping the real fields here means
+/// `DashMap` still compares them for equality on every lookup, the same way
+/// it would for any other key type.
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct CrudQueryDedupKey {
+    version_id: String,
+    user_id: Option<String>,
+    normalized_params: String,
+}
+
+/// Coalesces concurrent, identical `op_chisel_crud_query` calls into a
+/// single `crud::run_query`. Under bursty traffic many requests can land on
+/// the same version with the same `QueryParams` at once; the first caller
+/// becomes the leader and runs the query, the rest just await its result
+/// instead of each issuing their own. Scoped to read-only queries only:
+/// never use this for mutations or streaming relational queries, where a
+/// shared, possibly-stale result would be wrong.
+#[derive(Default)]
+pub struct CrudQueryDedup {
+    pending: DashMap<CrudQueryDedupKey, Vec<oneshot::Sender<Result<JsonObject, String>>>>,
+}
+
+impl CrudQueryDedup {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Removes `key`'s pending entry (if any) and errors out any waiters
+    /// queued behind it. Used via `LeaderGuard` when the leader's future is
+    /// dropped before it finishes (a completed leader, `Ok` or `Err`, hands
+    /// its result to the waiters in `run` itself) — otherwise a cancelled leader (request cancellation,
+    /// isolate teardown) would leave the entry stuck forever, since the
+    /// waiters' senders live in the `DashMap`, not in the leader's future.
+    fn fail_pending(&self, key: &CrudQueryDedupKey, message: &str) {
+        if let Some((_, waiters)) = self.pending.remove(key) {
+            for waiter in waiters {
+                let _ = waiter.send(Err(message.to_string()));
+            }
+        }
+    }
+
+    async fn run<F, Fut>(&self, key: CrudQueryDedupKey, query: F) -> Result<JsonObject>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<JsonObject>>,
+    {
+        let waiter = match self.pending.entry(key.clone()) {
+            Entry::Occupied(mut entry) => {
+                let (tx, rx) = oneshot::channel();
+                entry.get_mut().push(tx);
+                Some(rx)
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(Vec::new());
+                None
+            }
+        };
+
+        // Not the leader: await the leader's result instead of re-running
+        // the query. If the leader's transaction rolled back, we get the
+        // same error rather than a stale value.
+        if let Some(rx) = waiter {
+            return rx
+                .await
+                .context("crud query leader disappeared without a result")?
+                .map_err(|message| anyhow!(message));
+        }
+
+        // We're the leader. Guard against this future being dropped before
+        // `query()` resolves: without this, a cancelled leader leaves its
+        // waiters parked on a `DashMap` entry that nothing will ever clean
+        // up or send to.
+        let mut guard = LeaderGuard {
+            dedup: self,
+            key: key.clone(),
+            completed: false,
+        };
+        let result = query().await;
+        guard.completed = true;
+
+        let waiters = self
+            .pending
+            .remove(&key)
+            .map(|(_, waiters)| waiters)
+            .unwrap_or_default();
+        for waiter in waiters {
+            let resent = match &result {
+                Ok(value) => Ok(value.clone()),
+                Err(err) => Err(format!("{err:#}")),
+            };
+            let _ = waiter.send(resent);
+        }
+        result
+    }
+}
+
+/// On drop, cleans up its leader's dedup entry unless `completed` was set
+/// first. Covers the cancellation path: if the leader's future is dropped
+/// while `query()` is still pending, this still runs and unblocks waiters
+/// with an error instead of leaving them parked forever.
+struct LeaderGuard<'a> {
+    dedup: &'a CrudQueryDedup,
+    key: CrudQueryDedupKey,
+    completed: bool,
+}
+
+impl Drop for LeaderGuard<'_> {
+    fn drop(&mut self) {
+        if !self.completed {
+            self.dedup.fail_pending(
+                &self.key,
+                "crud query leader was cancelled before completing",
+            );
+        }
+    }
+}
+
+/// Builds the `(version_id, user_id, params)` key identical concurrent
+/// `op_chisel_crud_query` calls from the *same caller* land on. Policies
+/// apply row-level filtering based on the caller's identity (see
+/// `RequestContext::new`), so `user_id` must be part of the key: otherwise a
+/// query result computed under one caller's policy filtering could be
+/// handed out to a different caller waiting on the "same" nominal query.
+/// Deliberately does *not* include `headers`: most headers (trace ids,
+/// `Date`, cookies, `If-None-Match`, ...) vary per request even when the
+/// logical query is identical, so folding the whole header list in would
+/// defeat coalescing in practice. Policies only key row-level filtering off
+/// `user_id`, not arbitrary headers, so `user_id` alone is sufficient to
+/// keep callers from seeing each other's filtered results. `params` is
+/// hashed via its JSON form rather than a derived `Hash` impl, since that's
+/// the normalized shape already used to compare queries elsewhere.
+fn crud_query_dedup_key(
+    context: &ChiselRequestContext,
+    params: &crud::QueryParams,
+) -> Result<CrudQueryDedupKey> {
+    let normalized_params = serde_json::to_string(params)
+        .context("failed to normalize query params for deduplication")?;
+    Ok(CrudQueryDedupKey {
+        version_id: context.version_id.clone(),
+        user_id: context.user_id.clone(),
+        normalized_params,
+    })
+}
+
 #[deno_core::op]
 pub async fn op_chisel_crud_query(
     state: Rc<RefCell<deno_core::OpState>>,
     params: crud::QueryParams,
     context: ChiselRequestContext,
 ) -> Result<JsonObject> {
-    with_transaction(state, move |server, version, transaction| async move {
-        crud::run_query(
-            &RequestContext::new(&version.policy_system, &version.type_system, context),
-            params,
-            server.query_engine.clone(),
-            transaction,
-        )
+    let (server, dedup_key) = {
+        let state = state.borrow();
+        let worker_state = state.borrow::<WorkerState>();
+        let dedup_key = crud_query_dedup_key(&context, &params)?;
+        (worker_state.server.clone(), dedup_key)
+    };
+
+    server
+        .crud_query_dedup
+        .run(dedup_key, move || {
+            with_transaction(state, move |server, version, transaction| async move {
+                crud::run_query(
+                    &RequestContext::new(&version.policy_system, &version.type_system, context),
+                    params,
+                    server.query_engine.clone(),
+                    transaction,
+                )
+                .await
+            })
+        })
         .await
-    })
-    .await
 }
 
 #[deno_core::op]
@@ -356,3 +751,38 @@ impl RequestContext<'_> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `crud::QueryParams` isn't constructible in isolation (it comes from
+    /// the request body), so this exercises `CrudQueryDedupKey` itself
+    /// rather than going through `crud_query_dedup_key`: different callers
+    /// must land on different dedup keys even when everything else about
+    /// the query matches, and the same caller/query must reuse one.
+    #[test]
+    fn dedup_key_distinguishes_different_callers() {
+        let alice = CrudQueryDedupKey {
+            version_id: "v1".to_string(),
+            user_id: Some("alice".to_string()),
+            normalized_params: "{}".to_string(),
+        };
+        let bob = CrudQueryDedupKey {
+            user_id: Some("bob".to_string()),
+            ..alice.clone()
+        };
+        assert_ne!(alice, bob);
+
+        let mut seen = std::collections::HashSet::new();
+        assert!(seen.insert(alice.clone()));
+        assert!(
+            seen.insert(bob),
+            "different callers must not collide onto the same dedup key"
+        );
+        assert!(
+            !seen.insert(alice),
+            "the same caller/query should reuse the same dedup key"
+        );
+    }
+}
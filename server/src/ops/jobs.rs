@@ -0,0 +1,139 @@
+// SPDX-FileCopyrightText: © 2022 ChiselStrike <info@chiselstrike.com>
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use anyhow::{Context, Result};
+use serde_derive::Deserialize;
+use serde_json::Value as JsonValue;
+
+use super::datastore::{with_transaction, ChiselRequestContext};
+use super::WorkerState;
+use crate::jobs::{Job, JobId};
+
+/// Parameters for `op_chisel_enqueue_job`, mirroring the shape of a job
+/// accepted by `chiselstrike.jobs.enqueue(queue, payload)` in chisel.ts.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EnqueueJobParams {
+    queue_name: String,
+    payload: JsonValue,
+}
+
+/// Declares that `queue_name` has a JS handler registered for this version,
+/// via `chiselstrike.jobs.handle(queueName, handler)` in chisel.ts. Called
+/// once at module init time, the same way routes get registered, so the
+/// worker loop (see `crate::jobs::JobWorker`) knows which `(version, queue)`
+/// pairs to poll. Scoped by `context.version_id()` so two versions picking the
+/// same queue name never end up sharing one queue.
+#[deno_core::op]
+pub fn op_chisel_register_job_queue(
+    state: &mut deno_core::OpState,
+    queue_name: String,
+    context: ChiselRequestContext,
+) -> Result<()> {
+    let worker_state = state.borrow::<WorkerState>();
+    worker_state
+        .server
+        .job_queue_registry
+        .register(context.version_id().to_string(), queue_name);
+    Ok(())
+}
+
+/// Inserts a `new` row into `chisel_job_queue` using the transaction already
+/// bound to this request, so enqueueing a job is atomic with whatever else
+/// the route handler is doing. Tagged with `context.version_id()` so the job
+/// is only ever claimed by that version's worker.
+#[deno_core::op]
+pub async fn op_chisel_enqueue_job(
+    state: Rc<RefCell<deno_core::OpState>>,
+    params: EnqueueJobParams,
+    context: ChiselRequestContext,
+) -> Result<JobId> {
+    with_transaction(state, move |server, _version, transaction| async move {
+        let mut transaction = transaction.lock().await;
+        server
+            .job_queue
+            .enqueue(
+                context.version_id(),
+                &params.queue_name,
+                params.payload,
+                &mut transaction,
+            )
+            .await
+            .context("failed to enqueue job")
+    })
+    .await
+}
+
+/// Claims the next available job for `queue_name` scoped to
+/// `context.version_id()`, marking it `running` so no other worker picks it up
+/// concurrently. Returns `None` when the queue is empty.
+#[deno_core::op]
+pub async fn op_chisel_dequeue(
+    state: Rc<RefCell<deno_core::OpState>>,
+    queue_name: String,
+    context: ChiselRequestContext,
+) -> Result<Option<Job>> {
+    let server = state.borrow().borrow::<WorkerState>().server.clone();
+    server
+        .job_queue
+        .claim_next(context.version_id(), &queue_name)
+        .await
+        .context("failed to claim next job")
+}
+
+/// Updates the heartbeat of a `running` job so the reaper does not mistake
+/// it for abandoned work while its handler is still making progress. Checks
+/// that `job_id` belongs to `context.version_id()` first, since `job_id` comes
+/// from an untrusted JS caller.
+#[deno_core::op]
+pub async fn op_chisel_job_heartbeat(
+    state: Rc<RefCell<deno_core::OpState>>,
+    job_id: JobId,
+    context: ChiselRequestContext,
+) -> Result<()> {
+    let server = state.borrow().borrow::<WorkerState>().server.clone();
+    server
+        .job_queue
+        .touch_heartbeat_by_id(context.version_id(), job_id)
+        .await
+}
+
+/// Marks a previously claimed job as `complete`. Checks that `job_id`
+/// belongs to `context.version_id()` first, since `job_id` comes from an
+/// untrusted JS caller.
+#[deno_core::op]
+pub async fn op_chisel_complete_job(
+    state: Rc<RefCell<deno_core::OpState>>,
+    job_id: JobId,
+    context: ChiselRequestContext,
+) -> Result<()> {
+    let server = state.borrow().borrow::<WorkerState>().server.clone();
+    server
+        .job_queue
+        .complete_by_id(context.version_id(), job_id)
+        .await
+}
+
+/// Reschedules a previously claimed job after its handler threw `error`,
+/// following the retry/backoff policy in [`crate::jobs::JobQueue::fail`]; the
+/// job is dead-lettered once it runs out of retries. Takes only `job_id` and
+/// `context`: the retry/backoff decision is made from the row's current
+/// state in `chisel_job_queue`, not from caller-supplied counters, since the
+/// caller is a JS queue-handler script that shouldn't be trusted to report
+/// its own retry count honestly, and `context.version_id()` is checked against
+/// the row before anything is mutated.
+#[deno_core::op]
+pub async fn op_chisel_fail_job(
+    state: Rc<RefCell<deno_core::OpState>>,
+    job_id: JobId,
+    error: String,
+    context: ChiselRequestContext,
+) -> Result<()> {
+    let server = state.borrow().borrow::<WorkerState>().server.clone();
+    server
+        .job_queue
+        .fail_by_id(context.version_id(), job_id, error)
+        .await
+}